@@ -1,53 +1,117 @@
 use std::fs::{self, File};
-use std::io::{self, Read};
+use std::io::{self, BufRead, IsTerminal, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::sync::Arc;
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use chrono::{SecondsFormat, Utc};
 use clap::{Parser, Subcommand};
-use scopeguard::defer;
-use serde::Deserialize;
-use serde_json::json;
-use thiserror::Error;
-use toml;
-use tracing::{debug, error, info, instrument};
-use tracing_subscriber::{self, fmt, prelude::*, EnvFilter};
-
-#[derive(Error, Debug)]
-enum UlbError {
-    #[error("IO error: {0}")]
-    Io(#[from] io::Error),
-    #[error("TOML parse error: {0}")]
-    Toml(#[from] toml::de::Error),
-    #[error("Command failed in {stage}: {message}")]
-    Command { stage: String, message: String },
-    #[error("Unsupported distro: {0}")]
-    UnsupportedDistro(String),
-    #[error("JSON error: {0}")]
-    Json(#[from] serde_json::Error),
-    #[error("Validation error: {0}")]
-    Validation(String),
-    // Add more as needed
-}
-
-#[derive(Deserialize, Debug, Clone)]
-struct Config {
-    distro: String,
-    image_name: String,
-    installer: Option<String>,
-    architecture: Option<String>, // For cross-compilation
-    // More fields
-}
+use tracing::info;
+use tracing_subscriber::{self, fmt, prelude::*, EnvFilter, Layer};
+use ulb_backend::{boot_test, build_overwrites, clean_cache, clean_paths, config_schema, doctor, install_signal_handlers, list_distros, load_config, parse_source_date_epoch, run_build, status, validate_config, remove_build_paths, validate_project, BuildDaemon, BuildOptions, Config, BuildProfile, ProgressHub, ProgressServer, UlbError};
 
 #[derive(Subcommand, Debug)]
 enum Commands {
     Build {
         #[clap(long)]
         release: bool,
+        /// release, debug or minimal; fills in compression, checksums and strip unless the config sets them
+        #[clap(long, conflicts_with = "release")]
+        profile: Option<BuildProfile>,
         #[clap(long)]
         json_output: bool,
+        #[clap(long)]
+        dry_run: bool,
+        /// Reuse an existing builder container and skip stages that already completed
+        #[clap(long)]
+        resume: bool,
+        /// Where to write the ISO, defaults to build/release/<image_name>.iso
+        #[clap(long)]
+        output: Option<PathBuf>,
+        /// Rebuild the rootfs even when a cached snapshot matches
+        #[clap(long)]
+        no_cache: bool,
+        /// Also append the JSON progress events to this file or FIFO, one per line
+        #[clap(long)]
+        progress_file: Option<PathBuf>,
+        /// Serve GET /progress and GET /events (SSE) on 127.0.0.1:<port> while building
+        #[clap(long)]
+        serve_progress: Option<u16>,
+        /// Build all variants at the same time instead of one after another
+        #[clap(long)]
+        parallel: bool,
+        /// How many builds of a distros list run at once, defaults to all of them
+        #[clap(long)]
+        jobs: Option<usize>,
+        /// Remove any existing builder container and start from a new one
+        #[clap(long, conflicts_with = "resume")]
+        fresh: bool,
+        /// Leave the builder container in place after the build, even when it fails
+        #[clap(long)]
+        keep_container: bool,
+        /// Run only this stage in the existing builder container; repeat for several, they run in pipeline order
+        #[clap(long = "only-stage", conflicts_with = "fresh")]
+        only_stage: Vec<String>,
+        /// Fail unless every package list entry pins a version (name=version, or name-version on Fedora) the repositories have
+        #[clap(long)]
+        frozen: bool,
+        /// Fedora release to build instead of the config's release_version
+        #[clap(long)]
+        release_version: Option<String>,
+        /// Debian or Ubuntu suite to build instead of the config's suite
+        #[clap(long)]
+        suite: Option<String>,
+        /// Warn about failing scripts in scripts/ and run the rest, except those marked `# ulb: critical`
+        #[clap(long)]
+        continue_on_script_error: bool,
+        /// Remove a rootfs an interrupted build left half-populated instead of failing
+        #[clap(long)]
+        force_clean_rootfs: bool,
+        /// Don't ask before overwriting an existing rootfs or image
+        #[clap(short, long)]
+        yes: bool,
+    },
+    /// Wipe the download cache, or with flags the rootfs, release artifacts or whole build dir
+    Clean {
+        /// Only wipe the cache of the configured distro
+        #[clap(long)]
+        distro_only: bool,
+        /// Remove build/rootfs and its stage markers instead of the cache
+        #[clap(long)]
+        rootfs: bool,
+        /// Remove the ISOs, checksums and manifests in build/release instead of the cache
+        #[clap(long)]
+        release: bool,
+        /// Remove the entire build directory, after confirming
+        #[clap(long, conflicts_with_all = ["distro_only", "rootfs", "release"])]
+        all: bool,
+        /// Don't ask before removing the build directory with --all
+        #[clap(short, long)]
+        yes: bool,
+    },
+    Status {
+        #[clap(long)]
+        json: bool,
+    },
+    Validate,
+    /// Boot the newest ISO in build/release under QEMU and wait for the boot marker
+    Test,
+    /// Print the supported distros with their default architecture and image
+    ListDistros {
+        #[clap(long)]
+        json: bool,
+    },
+    /// Print the JSON Schema of the config file
+    Schema,
+    /// Check the host for what builds need: a container engine, disk space, binfmt, loop devices and builder tools
+    Doctor,
+    /// Accept build jobs as JSON lines on a Unix socket and stream their progress back
+    Daemon {
+        #[clap(long)]
+        socket: PathBuf,
+        /// How many jobs build at once
+        #[clap(long, default_value_t = 1)]
+        workers: usize,
     },
-    Clean,
-    Status,
 }
 
 #[derive(Parser, Debug)]
@@ -55,470 +119,408 @@ enum Commands {
 struct Args {
     #[clap(subcommand)]
     command: Commands,
-    config_path: PathBuf,
+    /// Project config, required by every command except list-distros, schema, daemon and doctor
+    config_path: Option<PathBuf>,
+    /// Log file path, defaults to build/logs/build-<timestamp>.log
+    #[clap(long, global = true)]
+    log_file: Option<PathBuf>,
+    /// Roll the log file over to <name>.1 once it grows past this many MB
+    #[clap(long, global = true, default_value_t = 100)]
+    max_log_size: u64,
+    /// How many rolled-over log files to keep next to the current one
+    #[clap(long, global = true, default_value_t = 5)]
+    log_keep: usize,
+    /// Fail on unknown or renamed config fields instead of warning
+    #[clap(long, global = true)]
+    strict_config: bool,
+    /// Show more output: -v info, -vv debug, -vvv trace (overrides RUST_LOG)
+    #[clap(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Only show errors (overrides RUST_LOG)
+    #[clap(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
 }
 
-fn main() -> Result<(), UlbError> {
-    // Setup logging
-    tracing_subscriber::registry()
-        .with(fmt::layer())
-        .with(EnvFilter::from_default_env())
-        .init();
+fn main() -> ExitCode {
     let args = Args::parse();
-    let config_dir = args.config_path.parent().unwrap_or(Path::new("."));
-    let mut config_file = File::open(&args.config_path)?;
-    let mut config_str = String::new();
-    config_file.read_to_string(&mut config_str)?;
-    let config: Config = toml::from_str(&config_str)?;
-    validate_config(&config, config_dir)?;
-    match args.command {
-        Commands::Build { release, json_output } => {
-            let distro = create_distro_backend(&config)?;
-            distro.build_iso(release, json_output)?;
+    let json_errors = matches!(args.command, Commands::Build { json_output: true, .. });
+    match init_logging(&args).and_then(|()| install_signal_handlers()).and_then(|()| run(args)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}", error_report(&err, json_errors));
+            ExitCode::from(err.exit_code())
         }
-        Commands::Clean => clean_cache()?,
-        Commands::Status => status(&config, &args.config_path)?,
     }
-    Ok(())
 }
 
-fn validate_config(config: &Config, config_dir: &Path) -> Result<(), UlbError> {
-    if !["fedora", "debian"].contains(&config.distro.as_str()) {
-        return Err(UlbError::Validation(format!("Unsupported distro: {}", config.distro)));
-    }
-    if config.image_name.is_empty() {
-        return Err(UlbError::Validation("image_name cannot be empty".to_string()));
-    }
-    let package_list_path = config_dir.join("package-lists");
-    if !package_list_path.exists() || package_list_path.metadata()?.len() == 0 {
-        return Err(UlbError::Validation("package-lists file is missing or empty".to_string()));
-    }
+fn init_logging(args: &Args) -> Result<(), UlbError> {
+    let log_path = args.log_file.clone().unwrap_or_else(|| default_log_path(Path::new(".")));
+    let level = verbosity_level(args.verbose, args.quiet);
+    let console_filter = level.map_or_else(EnvFilter::from_default_env, EnvFilter::new);
+    tracing_subscriber::registry()
+        .with(fmt::layer().with_filter(console_filter))
+        .with(log_file_layer(&log_path, level, max_log_bytes(args.max_log_size)?, args.log_keep)?)
+        .init();
     Ok(())
 }
 
-fn clean_cache() -> Result<(), UlbError> {
-    let cache_dir = Path::new("build/.cache");
-    if cache_dir.exists() {
-        fs::remove_dir_all(cache_dir)?;
-        info!("Cache cleaned");
-    }
-    Ok(())
+fn max_log_bytes(max_log_size: u64) -> Result<u64, UlbError> {
+    max_log_size.checked_mul(1024 * 1024).ok_or_else(|| UlbError::Validation(format!("--max-log-size {} is too large", max_log_size)))
 }
 
-fn status(config: &Config, config_path: &PathBuf) -> Result<(), UlbError> {
-    println!("ULB Backend Version: 0.2.0");
-    println!("Config Path: {}", config_path.display());
-    println!("Distro: {}", config.distro);
-    println!("Image Name: {}", config.image_name);
-    if let Some(installer) = &config.installer {
-        println!("Installer: {}", installer);
-    }
-    if let Some(arch) = &config.architecture {
-        println!("Architecture: {}", arch);
-    }
-    let podman_status = Command::new("podman").arg("--version").status();
-    match podman_status {
-        Ok(status) if status.success() => println!("Podman is available."),
-        _ => println!("Warning: Podman is not available or not in PATH."),
+// JSON for --json-output builds, so the frontend can tell failures apart by code
+fn error_report(err: &UlbError, json: bool) -> String {
+    if json { err.to_json().to_string() } else { format!("Error: {}", err) }
+}
+
+fn default_log_path(base_dir: &Path) -> PathBuf {
+    let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+    base_dir.join("build/logs").join(format!("build-{}.log", timestamp))
+}
+
+// None leaves the console filter to RUST_LOG, whose default matches the "error" level
+fn verbosity_level(verbose: u8, quiet: bool) -> Option<&'static str> {
+    match (quiet, verbose) {
+        (true, _) => Some("error"),
+        (false, 0) => None,
+        (false, 1) => Some("info"),
+        (false, 2) => Some("debug"),
+        (false, _) => Some("trace"),
     }
-    Ok(())
 }
 
-// Trait for Distro-specific logic
-trait DistroBackend {
-    fn base(&self) -> &BaseBackend;
-    fn install_packages(&self, container: &str, json_output: bool) -> Result<(), UlbError>;
-    fn remove_packages(&self, container: &str, json_output: bool) -> Result<(), UlbError>;
-    fn build_rootfs(&self, container: &str, json_output: bool) -> Result<(), UlbError>;
-    fn install_installer(&self, container: &str, json_output: bool) -> Result<(), UlbError>;
-    fn install_custom_packages(&self, container: &str, json_output: bool) -> Result<(), UlbError>;
-    fn create_iso(&self, container: &str, release: bool, json_output: bool) -> Result<(), UlbError>;
+// The log file keeps info-level output unless RUST_LOG says otherwise, so failed remote builds can be inspected.
+// -vv and up also raise the file level; -q only quiets the console.
+fn log_file_layer<S>(path: &Path, level: Option<&str>, max_size: u64, keep: usize) -> Result<impl Layer<S>, UlbError>
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    let writer = RotatingWriter::open(path, max_size, keep)?;
+    let filter = match level {
+        Some(level @ ("debug" | "trace")) => EnvFilter::new(level),
+        _ => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+    Ok(fmt::layer().with_ansi(false).with_writer(Mutex::new(writer)).with_filter(filter))
 }
 
-// Base struct for common fields and methods
-#[derive(Debug)]
-struct BaseBackend {
-    config: Arc<Config>,
-    base_dir: PathBuf,
-    cache_dir: PathBuf,
-    release_dir: PathBuf,
-    container_image: String,
-    container_name: String,
+// Appends to the log file and, once it is past max_size, renames it to <name>.1 (shifting older ones up to
+// <name>.<keep>) before the next line. Rolling only between lines keeps every line whole in one of the files.
+struct RotatingWriter {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+    at_line_start: bool,
 }
 
-impl BaseBackend {
-    fn new(config: &Config, distro: &str, default_arch: &str, image_prefix: &str) -> Result<Self, UlbError> {
-        let base_dir = Path::new(".").canonicalize()?;
-        let build_dir = base_dir.join("build");
-        let cache_dir = build_dir.join(".cache");
-        let release_dir = build_dir.join("release");
-        fs::create_dir_all(&cache_dir)?;
-        fs::create_dir_all(&release_dir)?;
-        let arch = config.architecture.as_deref().unwrap_or(default_arch);
-        let container_image = format!("{}:latest-{}", image_prefix, arch);
-        let container_name = format!("ulb-{}-builder", distro);
-        Ok(Self {
-            config: Arc::new(config.clone()),
-            base_dir,
-            cache_dir,
-            release_dir,
-            container_image,
-            container_name,
-        })
+impl RotatingWriter {
+    fn open(path: &Path, max_size: u64, keep: usize) -> io::Result<Self> {
+        let file = File::options().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path: path.to_path_buf(), file, size, max_size, keep, at_line_start: true })
     }
 
-    #[instrument]
-    fn setup_container(&self, json_output: bool) -> Result<String, UlbError> {
-        self.emit_progress("setup_container", 0.0, json_output)?;
-        let status = Command::new("podman").arg("pull").arg(&self.container_image).status()?;
-        if !status.success() {
-            return Err(UlbError::Command { stage: "setup_container".to_string(), message: "Podman pull failed".to_string() });
-        }
-        let mut create_cmd = Command::new("podman");
-        create_cmd
-            .arg("create")
-            .arg("--name")
-            .arg(&self.container_name)
-            .arg("-v")
-            .arg(format!("{}:/workspace", self.base_dir.display()))
-            .arg("-v")
-            .arg(format!("{}:/cache", self.cache_dir.display()))
-            .arg(&self.container_image)
-            .arg("sleep")
-            .arg("infinity");
-        let status = create_cmd.status()?;
-        if !status.success() {
-            return Err(UlbError::Command { stage: "setup_container".to_string(), message: "Podman create failed".to_string() });
-        }
-        Command::new("podman").arg("start").arg(&self.container_name).status()?;
-        self.emit_progress("setup_container", 1.0, json_output)?;
-        Ok(self.container_name.clone())
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
     }
 
-    fn run_scripts(&self, container: &str, json_output: bool) -> Result<(), UlbError> {
-        self.emit_progress("run_scripts", 0.0, json_output)?;
-        let scripts_dir = self.base_dir.join("scripts");
-        if scripts_dir.exists() {
-            let mut entries: Vec<_> = fs::read_dir(&scripts_dir)?.collect::<Result<_, _>>()?;
-            entries.sort_by_key(|e| e.file_name());
-            for entry in entries {
-                if entry.path().extension().map_or(false, |e| e == "sh") {
-                    let script_path = entry.path();
-                    let script_name = script_path.file_name().unwrap().to_str().unwrap();
-                    podman_cp(&script_path, container, &format!("/tmp/{}", script_name))?;
-                    let run_cmd = format!("bash /tmp/{} && rm /tmp/{}", script_name, script_name);
-                    podman_exec(container, &[&run_cmd], "run_scripts")?;
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.keep == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                if self.rotated(n).exists() {
+                    fs::rename(self.rotated(n), self.rotated(n + 1))?;
                 }
             }
+            fs::rename(&self.path, self.rotated(1))?;
+            self.file = File::options().create(true).append(true).open(&self.path)?;
         }
-        self.emit_progress("run_scripts", 1.0, json_output)?;
+        self.size = 0;
         Ok(())
     }
+}
 
-    fn copy_files(&self, container: &str, json_output: bool) -> Result<(), UlbError> {
-        self.emit_progress("copy_files", 0.0, json_output)?;
-        let files_dir = self.base_dir.join("files");
-        if files_dir.exists() {
-            let dest = "/workspace/build/rootfs";
-            let copy_cmd = format!("cp -r /workspace/files/* {}", dest);
-            podman_exec(container, &[&copy_cmd], "copy_files")?;
+impl Write for RotatingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.at_line_start && self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
         }
-        let install_files_dir = self.base_dir.join("install-files");
-        if install_files_dir.exists() {
-            let install_dest = "/workspace/build/rootfs/opt/install-files"; // Example dest
-            podman_exec(container, &[&format!("mkdir -p {}", install_dest)], "copy_files")?;
-            let copy_install_cmd = format!("cp -r /workspace/install-files/* {}", install_dest);
-            podman_exec(container, &[&copy_install_cmd], "copy_files")?;
-        }
-        self.emit_progress("copy_files", 1.0, json_output)?;
-        Ok(())
-    }
-
-    fn emit_progress(&self, stage: &str, progress: f32, json_output: bool) -> Result<(), UlbError> {
-        if json_output {
-            let msg = json!({
-                "stage": stage,
-                "progress": progress,
-            });
-            println!("{}", msg);
-        } else {
-            info!("Stage: {}, Progress: {}", stage, progress);
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        if written > 0 {
+            self.at_line_start = buf[written - 1] == b'\n';
         }
-        Ok(())
+        Ok(written)
     }
 
-    fn cleanup_container(&self, container: &str) -> Result<(), UlbError> {
-        info!("Cleaning up container");
-        let _ = Command::new("podman").arg("stop").arg(container).status();
-        let _ = Command::new("podman").arg("rm").arg(container).status();
-        Ok(())
-    }
-
-    fn build_iso_pipeline(&self, backend: &dyn DistroBackend, release: bool, json_output: bool) -> Result<(), UlbError> {
-        let container = self.setup_container(json_output)?;
-        defer! {
-            let _ = self.cleanup_container(&container);
-        }
-        backend.install_packages(&container, json_output)?;
-        backend.remove_packages(&container, json_output)?;
-        self.run_scripts(&container, json_output)?;
-        backend.build_rootfs(&container, json_output)?;
-        self.copy_files(&container, json_output)?;
-        backend.install_installer(&container, json_output)?;
-        backend.install_custom_packages(&container, json_output)?;
-        backend.create_iso(&container, release, json_output)?;
-        Ok(())
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
     }
 }
 
-// Fedora
-struct FedoraBackend {
-    base: BaseBackend,
+// Project files are resolved next to the config; a bare `Config.toml` has an empty parent, which means the cwd
+fn config_dir(config_path: &Path) -> &Path {
+    config_path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."))
 }
 
-impl FedoraBackend {
-    fn new(config: &Config) -> Result<Self, UlbError> {
-        let base = BaseBackend::new(config, "fedora", "x86_64", "fedora")?;
-        Ok(Self { base })
-    }
+// Scripts and CI pipe stdout, so only interactive runs are asked
+fn should_prompt(yes: bool, is_terminal: bool, clobbered: &[PathBuf]) -> bool {
+    !yes && is_terminal && !clobbered.is_empty()
 }
 
-impl DistroBackend for FedoraBackend {
-    fn base(&self) -> &BaseBackend {
-        &self.base
-    }
+// Asks on stderr so the answer can be piped in while stdout stays clean
+fn confirm(question: &str, mut input: impl BufRead) -> Result<bool, UlbError> {
+    eprint!("{} [y/N] ", question);
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes"))
+}
 
-    fn install_packages(&self, container: &str, json_output: bool) -> Result<(), UlbError> {
-        self.base.emit_progress("install_packages", 0.0, json_output)?;
-        let make_cache_cmd = "dnf makecache --cachedir=/cache/dnf";
-        podman_exec(container, &[make_cache_cmd], "install_packages")?;
-        let package_list_path = self.base.base_dir.join("package-lists");
-        let mut packages = String::new();
-        File::open(&package_list_path)?.read_to_string(&mut packages)?;
-        let packages = packages.lines().collect::<Vec<_>>().join(" ");
-        let install_cmd = format!("dnf --cachedir=/cache/dnf install -y {}", packages.trim());
-        podman_exec(container, &[&install_cmd], "install_packages")?;
-        self.base.emit_progress("install_packages", 1.0, json_output)?;
-        Ok(())
-    }
+// Loads and validates the config the project commands work on.
+// A config path of `-` reads the TOML from stdin, resolving project files against the cwd.
+fn load_project(config_path: Option<PathBuf>, strict: bool, release_version: Option<&str>, suite: Option<&str>) -> Result<(Config, PathBuf, PathBuf), UlbError> {
+    let config_path = config_path.ok_or_else(|| UlbError::Validation("A config path is required".to_string()))?;
+    let from_stdin = config_path == Path::new("-");
+    let config_dir = if from_stdin { PathBuf::from(".") } else { config_dir(&config_path).to_path_buf() };
+    let mut config = if from_stdin { load_config(io::stdin().lock(), strict)? } else { load_config(File::open(&config_path)?, strict)? };
+    info!("Loaded config for {} ({})", config.image_name, config.distro);
+    // Overrides go in before validation so they are checked like the config fields
+    config.apply_overrides(release_version, suite)?;
+    if let Some(version) = release_version {
+        info!("Building Fedora release {}", version);
+    }
+    if let Some(suite) = suite {
+        info!("Building suite {}", suite);
+    }
+    validate_config(&config, &config_dir)?;
+    Ok((config, config_path, config_dir))
+}
 
-    fn remove_packages(&self, container: &str, json_output: bool) -> Result<(), UlbError> {
-        self.base.emit_progress("remove_packages", 0.0, json_output)?;
-        let remove_list_path = self.base.base_dir.join("packages-lists-remove");
-        if remove_list_path.exists() {
-            let mut packages = String::new();
-            File::open(&remove_list_path)?.read_to_string(&mut packages)?;
-            let packages = packages.lines().collect::<Vec<_>>().join(" ");
-            let remove_cmd = format!("dnf remove -y {}", packages.trim());
-            podman_exec(container, &[&remove_cmd], "remove_packages")?;
+fn run(args: Args) -> Result<(), UlbError> {
+    let strict = args.strict_config;
+    match args.command {
+        Commands::ListDistros { json } => list_distros(json),
+        Commands::Schema => println!("{}", serde_json::to_string_pretty(&config_schema())?),
+        // Every job names its own config
+        Commands::Daemon { socket, workers } => BuildDaemon::bind(&socket)?.serve(workers)?,
+        // A config is optional, it adds the checks specific to that project
+        Commands::Doctor => match &args.config_path {
+            Some(config_path) => doctor(Some(&load_config(File::open(config_path)?, strict)?), config_dir(config_path))?,
+            None => doctor(None, Path::new("."))?,
+        },
+        Commands::Build { release, profile, json_output, dry_run, resume, output, no_cache, progress_file, serve_progress, parallel, jobs, fresh, keep_container, only_stage, frozen, force_clean_rootfs, continue_on_script_error, yes, release_version, suite } => {
+            let (config, _, config_dir) = load_project(args.config_path, strict, release_version.as_deref(), suite.as_deref())?;
+            let progress_hub = serve_progress.map(|_| Arc::new(ProgressHub::default()));
+            let opts = BuildOptions { release, profile, json_output, dry_run, resume, fresh, keep_container, output, no_cache, progress_hub: progress_hub.clone(), progress_file, parallel, jobs, only_stages: only_stage, frozen, force_clean_rootfs, continue_on_script_error, source_date_epoch: parse_source_date_epoch(std::env::var("SOURCE_DATE_EPOCH").ok().as_deref())?, base_dir: config_dir, ..Default::default() };
+            // Dry runs never touch the build dir
+            let clobbered = build_overwrites(&config, &opts);
+            if should_prompt(yes || dry_run, io::stdout().is_terminal(), &clobbered) {
+                let paths: Vec<String> = clobbered.iter().map(|path| format!("  {}", path.display())).collect();
+                if !confirm(&format!("This build overwrites:\n{}\nContinue?", paths.join("\n")), io::stdin().lock())? {
+                    println!("Aborted.");
+                    return Ok(());
+                }
+            }
+            let build = || run_build(config.clone(), opts.clone());
+            match (serve_progress, progress_hub) {
+                (Some(port), Some(hub)) => {
+                    let server = ProgressServer::start(hub.clone(), port)?;
+                    info!("Serving build progress on http://{}", server.addr);
+                    // The server answers from its own thread, so it stays up until the build finished and the hub closed
+                    let result = build();
+                    hub.finish(&result);
+                    drop(server);
+                    result?;
+                }
+                _ => build()?,
+            }
         }
-        self.base.emit_progress("remove_packages", 1.0, json_output)?;
-        Ok(())
-    }
-
-    fn build_rootfs(&self, container: &str, json_output: bool) -> Result<(), UlbError> {
-        self.base.emit_progress("build_rootfs", 0.0, json_output)?;
-        let rootfs_dir = "/workspace/build/rootfs";
-        fs::create_dir_all(self.base.base_dir.join("build/rootfs"))?;
-        let build_cmd = format!("dnf install --installroot {} --releasever=latest -y @core", rootfs_dir); // Example
-        podman_exec(container, &[&build_cmd], "build_rootfs")?;
-        self.base.emit_progress("build_rootfs", 1.0, json_output)?;
-        Ok(())
-    }
-
-    fn install_installer(&self, container: &str, json_output: bool) -> Result<(), UlbError> {
-        self.base.emit_progress("install_installer", 0.0, json_output)?;
-        if let Some(installer) = &self.base.config.installer {
-            let install_cmd = format!("dnf install -y {}", installer);
-            podman_exec(container, &[&install_cmd], "install_installer")?;
+        Commands::Clean { distro_only, rootfs, release, all, yes } => {
+            let (config, _, base_dir) = load_project(args.config_path, strict, None, None)?;
+            let base_dir = base_dir.as_path();
+            if all {
+                let build_dir = base_dir.join("build");
+                if !yes && !confirm(&format!("Remove {} and everything in it?", build_dir.display()), io::stdin().lock())? {
+                    println!("Aborted.");
+                    return Ok(());
+                }
+                remove_build_paths(&[build_dir])?;
+            } else if rootfs || release {
+                remove_build_paths(&clean_paths(&config, base_dir, rootfs, release))?;
+            } else {
+                clean_cache(&config, base_dir, distro_only)?;
+            }
         }
-        self.base.emit_progress("install_installer", 1.0, json_output)?;
-        Ok(())
-    }
-
-    fn install_custom_packages(&self, container: &str, json_output: bool) -> Result<(), UlbError> {
-        self.base.emit_progress("install_custom_packages", 0.0, json_output)?;
-        let repos_dir = self.base.base_dir.join("repos");
-        if repos_dir.exists() {
-            let copy_cmd = "cp /workspace/repos/* /etc/yum.repos.d/";
-            podman_exec(container, &[copy_cmd], "install_custom_packages")?;
-            let update_cmd = "dnf update -y";
-            podman_exec(container, &[update_cmd], "install_custom_packages")?;
+        Commands::Status { json } => {
+            let (config, config_path, _) = load_project(args.config_path, strict, None, None)?;
+            status(&config, &config_path, json)?
+        }
+        Commands::Validate => {
+            let (config, _, config_dir) = load_project(args.config_path, strict, None, None)?;
+            validate_project(&config, &config_dir)?
+        }
+        Commands::Test => {
+            let (config, _, config_dir) = load_project(args.config_path, strict, None, None)?;
+            boot_test(&config, &config_dir)?
         }
-        self.base.emit_progress("install_custom_packages", 1.0, json_output)?;
-        Ok(())
-    }
-
-    fn create_iso(&self, container: &str, release: bool, json_output: bool) -> Result<(), UlbError> {
-        self.base.emit_progress("create_iso", 0.0, json_output)?;
-        // Use lorax for Fedora live ISO
-        let iso_name = if release { "release.iso" } else { "debug.iso" };
-        let lorax_cmd = format!("lorax -p {} -v latest -r latest --rootfs-size=3 --buildarch={} -s http://download.fedoraproject.org/pub/fedora/linux/releases/latest/Everything/{}/os/ --isfinal={} /workspace/build/release/{}", self.base.config.image_name, self.base.config.architecture.as_deref().unwrap_or("x86_64"), self.base.config.architecture.as_deref().unwrap_or("x86_64"), release, iso_name);
-        podman_exec(container, &[&lorax_cmd], "create_iso")?;
-        self.base.emit_progress("create_iso", 1.0, json_output)?;
-        Ok(())
-    }
-}
-
-// Debian
-struct DebianBackend {
-    base: BaseBackend,
-}
-
-impl DebianBackend {
-    fn new(config: &Config) -> Result<Self, UlbError> {
-        let base = BaseBackend::new(config, "debian", "amd64", "debian")?;
-        Ok(Self { base })
     }
+    Ok(())
 }
 
-impl DistroBackend for DebianBackend {
-    fn base(&self) -> &BaseBackend {
-        &self.base
-    }
+// Tests
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
 
-    fn install_packages(&self, container: &str, json_output: bool) -> Result<(), UlbError> {
-        self.base.emit_progress("install_packages", 0.0, json_output)?;
-        let package_list_path = self.base.base_dir.join("package-lists");
-        let mut packages = String::new();
-        File::open(&package_list_path)?.read_to_string(&mut packages)?;
-        let packages = packages.lines().collect::<Vec<_>>().join(" ");
-        let update_cmd = "apt update";
-        let install_cmd = format!("DEBIAN_FRONTEND=noninteractive apt install -y {}", packages.trim());
-        podman_exec(container, &[update_cmd, &install_cmd], "install_packages")?;
-        self.base.emit_progress("install_packages", 1.0, json_output)?;
-        Ok(())
-    }
+    #[test]
+    fn test_validate_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("Config.toml");
+        fs::write(&config_path, "distro = \"debian\"\nimage_name = \"test\"\n").unwrap();
+        fs::write(dir.path().join("package-lists"), "vim\n").unwrap();
+        fs::create_dir(dir.path().join("scripts")).unwrap();
+        let args = Args::parse_from([OsStr::new("ulb-backend"), config_path.as_os_str(), OsStr::new("validate")]);
+        assert!(matches!(args.command, Commands::Validate));
+        assert!(run(args).is_ok());
 
-    fn remove_packages(&self, container: &str, json_output: bool) -> Result<(), UlbError> {
-        self.base.emit_progress("remove_packages", 0.0, json_output)?;
-        let remove_list_path = self.base.base_dir.join("packages-lists-remove");
-        if remove_list_path.exists() {
-            let mut packages = String::new();
-            File::open(&remove_list_path)?.read_to_string(&mut packages)?;
-            let packages = packages.lines().collect::<Vec<_>>().join(" ");
-            let remove_cmd = format!("DEBIAN_FRONTEND=noninteractive apt remove -y {}", packages.trim());
-            podman_exec(container, &[&remove_cmd], "remove_packages")?;
-        }
-        self.base.emit_progress("remove_packages", 1.0, json_output)?;
-        Ok(())
+        fs::write(&config_path, "distro = \"gentoo\"\nimage_name = \"test\"\n").unwrap();
+        let args = Args::parse_from([OsStr::new("ulb-backend"), config_path.as_os_str(), OsStr::new("validate")]);
+        assert!(matches!(run(args), Err(UlbError::Validation(_))));
     }
 
-    fn build_rootfs(&self, container: &str, json_output: bool) -> Result<(), UlbError> {
-        self.base.emit_progress("build_rootfs", 0.0, json_output)?;
-        let rootfs_dir = "/workspace/build/rootfs";
-        fs::create_dir_all(self.base.base_dir.join("build/rootfs"))?;
-        let arch = self.base.config.architecture.as_deref().unwrap_or("amd64");
-        let build_cmd = format!("debootstrap --arch={} stable {} http://deb.debian.org/debian", arch, rootfs_dir);
-        podman_exec(container, &[&build_cmd], "build_rootfs")?;
-        self.base.emit_progress("build_rootfs", 1.0, json_output)?;
-        Ok(())
+    #[test]
+    fn test_log_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_path = dir.path().join("logs/nested/build.log");
+        let config_path = dir.path().join("Config.toml");
+        fs::write(&config_path, "distro = \"debian\"\nimage_name = \"test\"\n").unwrap();
+        fs::write(dir.path().join("package-lists"), "vim\n").unwrap();
+        let subscriber = tracing_subscriber::registry().with(log_file_layer(&log_path, None, 100 * 1024 * 1024, 5).unwrap());
+        let args = Args::parse_from([OsStr::new("ulb-backend"), config_path.as_os_str(), OsStr::new("validate"), OsStr::new("--log-file"), log_path.as_os_str()]);
+        assert_eq!(args.log_file.as_deref(), Some(log_path.as_path()));
+        tracing::subscriber::with_default(subscriber, || run(args)).unwrap();
+        let contents = fs::read_to_string(&log_path).unwrap();
+        assert!(contents.lines().count() >= 1);
+        assert!(default_log_path(Path::new(".")).starts_with("./build/logs"));
     }
 
-    fn install_installer(&self, container: &str, json_output: bool) -> Result<(), UlbError> {
-        self.base.emit_progress("install_installer", 0.0, json_output)?;
-        if let Some(installer) = &self.base.config.installer {
-            let install_cmd = format!("DEBIAN_FRONTEND=noninteractive apt install -y {}", installer);
-            podman_exec(container, &[&install_cmd], "install_installer")?;
-        }
-        self.base.emit_progress("install_installer", 1.0, json_output)?;
-        Ok(())
+    #[test]
+    fn test_config_dir() {
+        assert_eq!(config_dir(Path::new("Config.toml")), Path::new("."));
+        assert_eq!(config_dir(Path::new("project/Config.toml")), Path::new("project"));
+        assert_eq!(config_dir(Path::new("/srv/iso/Config.toml")), Path::new("/srv/iso"));
     }
 
-    fn install_custom_packages(&self, container: &str, json_output: bool) -> Result<(), UlbError> {
-        self.base.emit_progress("install_custom_packages", 0.0, json_output)?;
-        let repos_dir = self.base.base_dir.join("repos");
-        if repos_dir.exists() {
-            let copy_cmd = "cp /workspace/repos/* /etc/apt/sources.list.d/";
-            podman_exec(container, &[copy_cmd], "install_custom_packages")?;
-            let update_cmd = "apt update";
-            podman_exec(container, &[update_cmd], "install_custom_packages")?;
+    #[test]
+    fn test_log_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_path = dir.path().join("build.log");
+        let mut writer = RotatingWriter::open(&log_path, 64, 2).unwrap();
+        let lines: Vec<String> = (0..10).map(|n| format!("line {:02} of the build log\n", n)).collect();
+        for line in &lines {
+            // Split writes must not be torn apart by a rollover
+            let (head, tail) = line.split_at(5);
+            writer.write_all(head.as_bytes()).unwrap();
+            writer.write_all(tail.as_bytes()).unwrap();
         }
-        self.base.emit_progress("install_custom_packages", 1.0, json_output)?;
-        Ok(())
-    }
-
-    fn create_iso(&self, container: &str, release: bool, json_output: bool) -> Result<(), UlbError> {
-        self.base.emit_progress("create_iso", 0.0, json_output)?;
-        let iso_name = if release { "release.iso" } else { "debug.iso" };
-        let create_cmd = format!("xorriso -as mkisofs -o /workspace/build/release/{} /workspace/build/rootfs", iso_name);
-        podman_exec(container, &[&create_cmd], "create_iso")?;
-        self.base.emit_progress("create_iso", 1.0, json_output)?;
-        Ok(())
-    }
-}
-
-fn create_distro_backend(config: &Config) -> Result<Box<dyn DistroBackend>, UlbError> {
-    match config.distro.as_str() {
-        "fedora" => Ok(Box::new(FedoraBackend::new(config)?)),
-        "debian" => Ok(Box::new(DebianBackend::new(config)?)),
-        _ => Err(UlbError::UnsupportedDistro(config.distro.clone())),
-    }
-}
-
-impl dyn DistroBackend {
-    fn build_iso(&self, release: bool, json_output: bool) -> Result<(), UlbError> {
-        self.base().build_iso_pipeline(self, release, json_output)
+        writer.flush().unwrap();
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap_or_default();
+        let (current, first, second) = (read(log_path.clone()), read(writer.rotated(1)), read(writer.rotated(2)));
+        assert!(!first.is_empty() && !second.is_empty());
+        assert!(!writer.rotated(3).exists());
+        assert!(fs::metadata(&log_path).unwrap().len() <= 64);
+        // Only the oldest lines fall off the end, the rest are whole and in order
+        let kept = format!("{}{}{}", second, first, current);
+        assert!(lines.concat().ends_with(&kept));
+        assert!(kept.lines().all(|line| line.starts_with("line ") && line.ends_with(" of the build log")));
+        assert_eq!(Args::parse_from(["ulb-backend", "Config.toml", "build", "--max-log-size", "10", "--log-keep", "1"]).max_log_size, 10);
+        assert_eq!(max_log_bytes(10).unwrap(), 10 * 1024 * 1024);
+        assert!(matches!(max_log_bytes(u64::MAX), Err(UlbError::Validation(msg)) if msg.contains("--max-log-size")));
     }
-}
 
-fn podman_exec(container: &str, cmds: &[&str], stage: &str) -> Result<(), UlbError> {
-    for cmd in cmds {
-        let mut exec_cmd = Command::new("podman");
-        exec_cmd
-            .arg("exec")
-            .arg(container)
-            .arg("bash")
-            .arg("-c")
-            .arg(cmd);
-        let output = exec_cmd.output()?;
-        if !output.status.success() {
-            error!("Command failed in {}: {} - stderr: {}", stage, cmd, String::from_utf8_lossy(&output.stderr));
-            return Err(UlbError::Command { stage: stage.to_string(), message: format!("Command failed: {}", cmd) });
-        }
-        debug!("Command output in {}: {}", stage, String::from_utf8_lossy(&output.stdout));
+    #[test]
+    fn test_verbosity_level() {
+        assert_eq!(verbosity_level(0, false), None);
+        assert_eq!(verbosity_level(0, true), Some("error"));
+        assert_eq!(verbosity_level(1, false), Some("info"));
+        assert_eq!(verbosity_level(2, false), Some("debug"));
+        assert_eq!(verbosity_level(5, false), Some("trace"));
+        let args = Args::try_parse_from(["ulb-backend", "-vv", "Config.toml", "build"]).unwrap();
+        assert_eq!(verbosity_level(args.verbose, args.quiet), Some("debug"));
+        let args = Args::try_parse_from(["ulb-backend", "Config.toml", "validate", "-q"]).unwrap();
+        assert_eq!(verbosity_level(args.verbose, args.quiet), Some("error"));
+        assert!(Args::try_parse_from(["ulb-backend", "-v", "-q", "Config.toml", "validate"]).is_err());
     }
-    Ok(())
-}
 
-fn podman_cp(src: &Path, container: &str, dest: &str) -> Result<(), UlbError> {
-    let src_str = src.to_str().unwrap();
-    let cp_cmd = Command::new("podman")
-        .arg("cp")
-        .arg(src_str)
-        .arg(format!("{}:{}", container, dest))
-        .status()?;
-    if !cp_cmd.success() {
-        return Err(UlbError::Command { stage: "podman_cp".to_string(), message: "Podman cp failed".to_string() });
+    #[test]
+    fn test_error_report() {
+        let err = UlbError::Command { stage: "create_iso".to_string(), message: "xorriso failed".to_string() };
+        let report: serde_json::Value = serde_json::from_str(&error_report(&err, true)).unwrap();
+        assert_eq!(report["error"]["code"], "COMMAND_FAILED");
+        assert_eq!(report["error"]["stage"], "create_iso");
+        assert_eq!(error_report(&err, false), "Error: Command failed in create_iso: xorriso failed");
     }
-    Ok(())
-}
-
-// Tests
-#[cfg(test)]
-mod tests {
-    use super::*;
 
     #[test]
-    fn test_config_parse() {
-        let config_str = r#"
-distro = "fedora"
-image_name = "test"
-"#;
-        let config: Config = toml::from_str(config_str).unwrap();
-        assert_eq!(config.distro, "fedora");
+    fn test_should_prompt() {
+        let clobbered = [PathBuf::from("build/rootfs"), PathBuf::from("build/release/test.iso")];
+        assert!(should_prompt(false, true, &clobbered));
+        assert!(!should_prompt(true, true, &clobbered));
+        assert!(!should_prompt(false, false, &clobbered));
+        assert!(!should_prompt(false, true, &[]));
+        assert!(matches!(Args::parse_from(["ulb-backend", "Config.toml", "build", "-y"]).command, Commands::Build { yes: true, .. }));
     }
 
     #[test]
-    fn test_validate_config() {
-        let config = Config {
-            distro: "invalid".to_string(),
-            image_name: "test".to_string(),
-            installer: None,
-            architecture: None,
+    fn test_cli_flags() {
+        assert!(matches!(Args::parse_from(["ulb-backend", "list-distros", "--json"]).command, Commands::ListDistros { json: true }));
+        assert!(matches!(Args::parse_from(["ulb-backend", "schema"]).command, Commands::Schema));
+        assert!(matches!(Args::parse_from(["ulb-backend", "daemon", "--socket", "/run/ulb.sock"]).command, Commands::Daemon { workers: 1, .. }));
+        assert!(Args::try_parse_from(["ulb-backend", "daemon"]).is_err());
+        assert!(matches!(Args::parse_from(["ulb-backend", "doctor"]), Args { command: Commands::Doctor, config_path: None, .. }));
+        assert!(Args::try_parse_from(["ulb-backend", "Config.toml", "build", "--fresh", "--resume"]).is_err());
+        assert!(matches!(Args::parse_from(["ulb-backend", "Config.toml", "build", "--profile", "minimal"]).command, Commands::Build { profile: Some(BuildProfile::Minimal), .. }));
+        assert!(Args::try_parse_from(["ulb-backend", "Config.toml", "build", "--profile", "debug", "--release"]).is_err());
+        assert!(Args::try_parse_from(["ulb-backend", "Config.toml", "build", "--profile", "fast"]).is_err());
+        assert!(matches!(Args::parse_from(["ulb-backend", "Config.toml", "build", "--jobs", "2"]).command, Commands::Build { jobs: Some(2), .. }));
+        let Commands::Build { only_stage, .. } = Args::parse_from(["ulb-backend", "Config.toml", "build", "--only-stage", "build_bootloader", "--only-stage", "create_iso"]).command else {
+            panic!("expected a build command");
+        };
+        assert_eq!(only_stage, ["build_bootloader", "create_iso"]);
+        assert!(Args::try_parse_from(["ulb-backend", "Config.toml", "clean", "--all", "--rootfs"]).is_err());
+        let Commands::Build { release_version, suite, .. } = Args::parse_from(["ulb-backend", "Config.toml", "build", "--release-version", "42", "--suite", "trixie"]).command else {
+            panic!("expected a build command");
         };
-        assert!(validate_config(&config, Path::new(".")).is_err());
+        assert_eq!((release_version.as_deref(), suite.as_deref()), (Some("42"), Some("trixie")));
+        assert!(matches!(Args::parse_from(["ulb-backend", "Config.toml", "build", "--progress-file", "/run/ulb/progress"]).command, Commands::Build { progress_file: Some(_), .. }));
+        assert!(matches!(Args::parse_from(["ulb-backend", "Config.toml", "clean", "--rootfs", "--release"]).command, Commands::Clean { rootfs: true, release: true, all: false, .. }));
     }
 
-    // More tests...
+    #[test]
+    fn test_clean_all_confirmation() {
+        assert!(!confirm("Remove build?", io::Cursor::new("\n")).unwrap());
+        assert!(!confirm("Remove build?", io::Cursor::new("nope\n")).unwrap());
+        assert!(confirm("Remove build?", io::Cursor::new("Y\n")).unwrap());
+        assert!(confirm("Remove build?", io::Cursor::new("yes\n")).unwrap());
+        let args = Args::parse_from(["ulb-backend", "Config.toml", "clean", "--all", "-y"]);
+        assert!(matches!(args.command, Commands::Clean { all: true, yes: true, .. }));
+
+        // The build dir sits next to the config, not in the working directory
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("Config.toml");
+        fs::write(&config_path, "distro = \"debian\"\nimage_name = \"test\"\n").unwrap();
+        fs::write(dir.path().join("package-lists"), "vim\n").unwrap();
+        fs::create_dir_all(dir.path().join("build/rootfs")).unwrap();
+        run(Args::parse_from([OsStr::new("ulb-backend"), config_path.as_os_str(), OsStr::new("clean"), OsStr::new("--all"), OsStr::new("-y")])).unwrap();
+        assert!(!dir.path().join("build").exists());
+    }
 }
@@ -0,0 +1,16 @@
+use std::fs;
+use ulb_backend::{run_build, BuildOptions, Config, UlbError};
+
+#[test]
+fn test_run_build_dry_run() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("package-lists"), "vim\n").unwrap();
+    let config = Config { distro: "debian".to_string(), image_name: "library".to_string(), min_free_gb: Some(0), ..Default::default() };
+    let opts = BuildOptions { dry_run: true, base_dir: dir.path().to_path_buf(), ..Default::default() };
+    run_build(config.clone(), opts.clone()).unwrap();
+    // Nothing is built in dry-run mode
+    assert!(!dir.path().join("build/release/library.iso").exists());
+
+    let invalid = Config { distro: "gentoo".to_string(), ..config };
+    assert!(matches!(run_build(invalid, opts), Err(UlbError::Validation(_))));
+}
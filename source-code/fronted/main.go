@@ -76,6 +76,7 @@ var initCmd = &cobra.Command{
 # installer: Optional installer package (e.g., anaconda for fedora)
 # architecture: Optional architecture (e.g., x86_64)
 
+config_version = 1
 distro = "fedora"
 image_name = "my-live-iso"
 installer = "anaconda" # Optional